@@ -20,9 +20,18 @@ struct Cli {
     /// RPC Endpoint
     endpoint: String,
 
-    /// Method to use (0-normal fork, 1-debug prestate (default) )
+    /// Method to use (0-normal fork, 1-debug prestate (default), 2-proof-verified )
     #[arg(short, long)]
     method_type: Option<u8>,
+
+    /// Zero the tx fees and inflate the gas limit instead of replaying with the
+    /// transaction's real gas economics
+    #[arg(long)]
+    relax_gas: bool,
+
+    /// Report the storage/balance/nonce changes the transaction made
+    #[arg(long)]
+    state_diff: bool,
 }
 
 #[tokio::main]
@@ -46,11 +55,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         cli.tx_hash,
         cli.endpoint,
         method_type,
+        cli.relax_gas,
+        None,
+        cli.state_diff,
     )
     .await?;
 
-    for v in run_rs {
-        println!("{}", v);
+    for v in run_rs.log_lines {
+        match (v.file, v.line) {
+            (Some(file), Some(line)) => println!("{} ({}:{})", v.message, file, line),
+            _ => println!("{}", v.message),
+        }
+    }
+
+    if let Some(state_diff) = run_rs.state_diff {
+        for diff in state_diff {
+            println!("{:?}", diff);
+        }
     }
 
     Ok(())
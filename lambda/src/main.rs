@@ -11,22 +11,43 @@ async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
     // Extract some useful information from the request
     let log3_json: Log3Json = event.payload().expect("body error").expect("body error2");
 
-    let run_rs = log3_lib::run(
-        log3_json.chainid,
-        log3_json.etherscan_api_key,
-        log3_json.contract_address,
-        log3_json.tx_hash,
-        log3_json.endpoint,
-        log3_json.method.unwrap_or_default(),
-    )
-    .await?;
+    // When the payload carries batch fields, replay every target tx in a single
+    // fork and return the console logs grouped per transaction; otherwise fall
+    // back to the single-tx path.
+    let body = if log3_json.tx_hashes.is_some() || log3_json.block_number.is_some() {
+        let run_rs = log3_lib::run_batch(
+            log3_json.chainid,
+            log3_json.etherscan_api_key,
+            log3_json.contract_address,
+            log3_json.endpoint,
+            log3_json.tx_hashes,
+            log3_json.block_number,
+            log3_json.relax_gas.unwrap_or(false),
+        )
+        .await?;
+        json!(run_rs).to_string()
+    } else {
+        let run_rs = log3_lib::run(
+            log3_json.chainid,
+            log3_json.etherscan_api_key,
+            log3_json.contract_address,
+            log3_json.tx_hash,
+            log3_json.endpoint,
+            log3_json.method.unwrap_or_default(),
+            log3_json.relax_gas.unwrap_or(false),
+            log3_json.overrides,
+            log3_json.state_diff.unwrap_or(false),
+        )
+        .await?;
+        json!(run_rs).to_string()
+    };
 
     // Return something that implements IntoResponse.
     // It will be serialized to the right response event automatically by the runtime
     let resp = Response::builder()
         .status(200)
         .header("content-type", "application/json")
-        .body(json!(run_rs).to_string().into())
+        .body(body.into())
         .map_err(Box::new)?;
     Ok(resp)
 }
@@ -1,14 +1,17 @@
 pub mod models;
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
 use ethers_core::{
-    abi::Address,
+    abi::{decode as abi_decode, Address, Contract, ParamType, RawLog},
     types::{
-        BigEndianHash, Block, Bytes, Chain, GethDebugBuiltInTracerType, GethDebugTracerType,
-        GethDebugTracingOptions, GethTrace, GethTraceFrame, Log, PreStateFrame, Transaction, H256,
-        U256,
+        BigEndianHash, Block, BlockId, Bloom, BloomInput, Bytes, Chain, EIP1186ProofResponse,
+        GethDebugBuiltInTracerType, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, Log, PreStateFrame, PreStateMode, Transaction, H256, U256,
     },
-    utils::{hex, keccak256},
+    utils::{hex, keccak256, rlp::Rlp},
 };
 use ethers_etherscan::{
     contract::{Metadata, SourceCodeEntry, SourceCodeMetadata},
@@ -20,6 +23,7 @@ use eyre::Result;
 use foundry_common::compile::compile_from_source;
 use foundry_config::Config;
 use foundry_evm::{
+    debug::DebugArena,
     decode::decode_console_logs,
     executor::{
         backend::{DatabaseError, DatabaseResult},
@@ -27,15 +31,19 @@ use foundry_evm::{
         opts::EvmOpts,
         Backend, Bytecode, DeployResult, Env, Executor, ExecutorBuilder, RawCallResult, SpecId,
     },
+    trace::CallTraceArena,
     revm::{
         primitives::{ruint::Uint, AccountInfo, B256},
         Database,
     },
-    utils::{h160_to_b160, u256_to_ru256},
+    utils::{h160_to_b160, ru256_to_u256, u256_to_ru256},
 };
 use regex::Regex;
 
-use models::MethodType;
+use models::{
+    AccountDiff, AccountOverrideJson, BatchLog3Res, DecodedEvent, EventParam, Log3Res, LogLine,
+    MethodType, StorageDiff, TraceFrame, TxLog3,
+};
 
 pub async fn run(
     chainid: u64,
@@ -44,7 +52,10 @@ pub async fn run(
     tx_hash: String,
     endpoint: String,
     method_type: MethodType,
-) -> eyre::Result<Vec<String>> {
+    relax_gas: bool,
+    overrides: Option<HashMap<String, AccountOverrideJson>>,
+    state_diff: bool,
+) -> eyre::Result<Log3Res> {
     eprintln!("run started");
     let chain = Chain::try_from(chainid).unwrap();
     let mut contract_metadata =
@@ -67,10 +78,188 @@ pub async fn run(
 
     eprintln!("Compiled source code");
 
-    let produced_logs =
-        simulate_tx(endpoint, tx_hash, contract_address, bytecode, method_type).await?;
+    // The verified ABI lets us decode the real event stream the transaction
+    // emitted alongside the injected console output.
+    let abi = Contract::load(contract_metadata.abi.as_bytes()).unwrap_or_default();
+
+    // The deployed bytecode source map plus the patched sources let us map the
+    // program counter at each console.log emission back to a source location.
+    let source_map = contract_bytecode
+        .deployed_bytecode
+        .bytecode
+        .as_ref()
+        .and_then(|b| b.source_map.clone());
+    let source_index =
+        SourceMapIndex::build(&bytecode, source_map.as_deref(), metadata_sources(&patched_metadata));
+
+    let result = simulate_tx(
+        chainid,
+        endpoint,
+        tx_hash,
+        contract_address,
+        bytecode,
+        method_type,
+        relax_gas,
+        overrides,
+        state_diff,
+        &abi,
+        source_index.as_ref(),
+    )
+    .await?;
+
+    Ok(result)
+}
+
+/// Replay a batch of transactions — either an explicit list of hashes or every
+/// transaction in a block — under the console-patched bytecode, returning the
+/// console logs grouped per transaction.
+///
+/// The whole batch shares a single fork at `first_block - 1`: we apply the
+/// override once and then run the block's transactions sequentially (the same
+/// loop `prepare_fork_state_plain` uses), collecting `decode_console_logs` for
+/// each target rather than discarding them.
+pub async fn run_batch(
+    chainid: u64,
+    etherscan_api_key: String,
+    contract_address: String,
+    endpoint: String,
+    tx_hashes: Option<Vec<String>>,
+    block_number: Option<u64>,
+    relax_gas: bool,
+) -> eyre::Result<BatchLog3Res> {
+    let chain = Chain::try_from(chainid).unwrap();
+    let mut contract_metadata =
+        get_source_from_etherscan(chain, contract_address.clone(), etherscan_api_key).await?;
+
+    let patched_metadata = patch_metadata_source(&mut contract_metadata).await?;
+    let (_, contract_bytecode) = compile_from_source(&patched_metadata).await?;
+    let code = contract_bytecode
+        .deployed_bytecode
+        .bytecode
+        .unwrap()
+        .object
+        .as_bytes()
+        .unwrap()
+        .clone();
+
+    let figment = Config::figment().merge(("eth_rpc_url", endpoint.clone()));
+    let mut evm_opts = figment.extract::<EvmOpts>().unwrap();
+    let config = Config::from_provider(figment).sanitized();
+    let provider = get_provider(&config).await?;
+
+    // The set of transactions whose logs we keep; `None` means the whole block.
+    let targets: Option<HashSet<H256>> = match &tx_hashes {
+        Some(hashes) => Some(
+            hashes
+                .iter()
+                .map(|hash| H256::from_str(hash))
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        None => None,
+    };
+
+    let block = match block_number {
+        Some(number) => provider
+            .get_block_with_txs(number)
+            .await?
+            .ok_or_else(|| eyre::eyre!("block {} not found", number))?,
+        None => {
+            let first = H256::from_str(
+                tx_hashes
+                    .as_ref()
+                    .and_then(|hashes| hashes.first())
+                    .ok_or_else(|| eyre::eyre!("no tx_hashes or block_number provided"))?,
+            )?;
+            let tx = provider
+                .get_transaction(first)
+                .await?
+                .ok_or_else(|| eyre::eyre!("transaction {:?} not found", first))?;
+            provider
+                .get_block_with_txs(tx.block_hash.unwrap())
+                .await?
+                .unwrap()
+        }
+    };
+
+    let block_num = block.number.unwrap().as_u64();
+    evm_opts.fork_url = Some(config.get_rpc_url_or_localhost_http().unwrap().into_owned());
+    evm_opts.fork_block_number = Some(block_num - 1);
+
+    let env = evm_opts.evm_env().await.unwrap();
+    let db = Backend::spawn(evm_opts.get_fork(&config, env.clone())).await;
+    let spec = {
+        let metadata_spec = evm_spec(&config.evm_version);
+        let block_spec = hardfork_spec(chainid, block_num);
+        if (block_spec as u8) > (metadata_spec as u8) {
+            block_spec
+        } else {
+            metadata_spec
+        }
+    };
+    let builder = ExecutorBuilder::default()
+        .with_config(env)
+        .with_spec(spec)
+        .with_cheatcodes(CheatsConfig::new(&config, &evm_opts));
+
+    let mut executor = builder.build(db);
+    executor.set_tracing(true).set_trace_printer(false);
+
+    let mut env = configure_env_for_executor(&executor, block_num, &block, relax_gas);
+
+    let overrides = StateOverride::from([(
+        Address::from_str(contract_address.as_str()).unwrap(),
+        AccountOverride {
+            code: Some(code.clone()),
+            ..Default::default()
+        },
+    )]);
+    apply_state_override(executor.backend_mut(), overrides).unwrap();
+
+    let mut results = Vec::new();
+    let mut seen: HashSet<H256> = HashSet::new();
+    for replayed_tx in block.transactions.iter() {
+        let is_target = targets
+            .as_ref()
+            .map(|set| set.contains(&replayed_tx.hash))
+            .unwrap_or(true);
+
+        configure_tx_env(&mut env, replayed_tx);
+        if relax_gas {
+            env.tx.gas_price = Uint::from(1);
+            env.tx.gas_priority_fee = Some(Uint::from(1));
+            env.tx.gas_limit *= 2000;
+        }
+
+        let logs = if let Some(_) = replayed_tx.to {
+            executor.commit_tx_with_env(env.clone()).unwrap().logs
+        } else {
+            executor.deploy_with_env(env.clone(), None).unwrap().logs
+        };
+
+        if is_target {
+            seen.insert(replayed_tx.hash);
+            results.push(TxLog3 {
+                tx_hash: format!("{:?}", replayed_tx.hash),
+                log_lines: decode_console_logs(&logs),
+            });
+        }
+    }
 
-    Ok(produced_logs)
+    // A batch shares one fork at a single block, so any requested hash that
+    // lives in a different block never matched above. Surface it rather than
+    // returning a partial result that looks complete.
+    if let Some(targets) = &targets {
+        let missing: Vec<H256> = targets.difference(&seen).copied().collect();
+        if !missing.is_empty() {
+            return Err(eyre::eyre!(
+                "transactions {:?} were not found in block {}",
+                missing,
+                block_num
+            ));
+        }
+    }
+
+    Ok(BatchLog3Res { results })
 }
 
 async fn get_source_from_etherscan(
@@ -152,16 +341,22 @@ async fn patch_source_unit(source_unit: &String) -> String {
 }
 
 async fn simulate_tx(
+    chainid: u64,
     endpoint: String,
     tx_hash: String,
     contract_address: String,
     code: Bytes,
     method_type: MethodType,
-) -> Result<Vec<String>> {
+    relax_gas: bool,
+    overrides: Option<HashMap<String, AccountOverrideJson>>,
+    state_diff: bool,
+    abi: &Contract,
+    source_index: Option<&SourceMapIndex>,
+) -> Result<Log3Res> {
     let figment = Config::figment().merge(("eth_rpc_url", endpoint.clone()));
     let mut evm_opts = figment.extract::<EvmOpts>().unwrap();
     let config = Config::from_provider(figment).sanitized();
-    let provider = get_provider(&config);
+    let provider = get_provider(&config).await?;
 
     let mut tx = provider
         .get_transaction(H256::from_str(tx_hash.as_str()).unwrap())
@@ -177,15 +372,40 @@ async fn simulate_tx(
     evm_opts.fork_block_number = Some(tx.block_number.unwrap().as_u64() - 1);
 
     let env = evm_opts.evm_env().await.unwrap();
-    let db = Backend::spawn(evm_opts.get_fork(&config, env.clone())).await;
+    // `Plain` replays the block's transactions that precede the target and
+    // therefore needs a fork that can reach historical state; `Prestate` and
+    // `Verified` seed only the accounts the prestate tracer reports (`Verified`
+    // from proof-checked `eth_getProof` data), so they run against a fresh
+    // in-memory backend.
+    let db = match method_type {
+        MethodType::Plain => Backend::spawn(evm_opts.get_fork(&config, env.clone())).await,
+        MethodType::Prestate | MethodType::Verified => Backend::spawn(None).await,
+    };
+    // The spec baked into the contract metadata is whatever the contract was
+    // compiled against, which can predate the block we are replaying. Pick the
+    // later of the two so opcode availability and gas behavior match the fork
+    // the transaction actually executed under.
+    let metadata_spec = evm_spec(&config.evm_version);
+    let block_spec = hardfork_spec(chainid, tx.block_number.unwrap().as_u64());
+    let spec = if (block_spec as u8) > (metadata_spec as u8) {
+        block_spec
+    } else {
+        metadata_spec
+    };
+
     let builder = ExecutorBuilder::default()
         .with_config(env)
-        .with_spec(evm_spec(&config.evm_version))
+        .with_spec(spec)
         .with_cheatcodes(CheatsConfig::new(&config, &evm_opts));
 
     let mut executor = builder.build(db);
 
-    let mut env = configure_env_for_executor(&executor, tx.block_number.unwrap().as_u64(), &block);
+    let mut env = configure_env_for_executor(
+        &executor,
+        tx.block_number.unwrap().as_u64(),
+        &block,
+        relax_gas,
+    );
 
     match method_type {
         MethodType::Plain => {
@@ -202,29 +422,68 @@ async fn simulate_tx(
                 .await
                 .unwrap();
         }
+        MethodType::Verified => {
+            prepare_fork_state_verified(
+                &mut executor,
+                &provider,
+                tx.hash.clone(),
+                tx.block_number.unwrap().as_u64() - 1,
+            )
+            .await
+            .unwrap();
+        }
     }
 
-    let overrides = StateOverride::from([(
-        Address::from_str(contract_address.as_str()).unwrap(),
-        AccountOverride {
-            code: Some(code.clone()),
-            ..Default::default()
-        },
-    )]);
+    // Caller-supplied overrides go in first; the instrumented console bytecode
+    // for the target contract is layered on top so it always wins.
+    let mut overrides = convert_state_overrides(overrides)?;
+    overrides
+        .entry(Address::from_str(contract_address.as_str()).unwrap())
+        .or_default()
+        .code = Some(code.clone());
 
     let _ = apply_state_override(executor.backend_mut(), overrides.clone()).unwrap();
 
+    // Snapshot the accounts/slots the tx touches (from the prestate tracer) so
+    // we can diff them after the commit below. Done after overrides are applied
+    // so the "before" value reflects the same starting state the tx executes on.
+    let touched = if state_diff {
+        let mode = fetch_prestate(&provider, tx.hash.clone()).await?;
+        mode.0
+            .into_iter()
+            .map(|(address, account)| {
+                let slots = account
+                    .storage
+                    .map(|storage| storage.into_keys().collect())
+                    .unwrap_or_default();
+                (address, slots)
+            })
+            .collect::<Vec<(Address, Vec<H256>)>>()
+    } else {
+        Vec::new()
+    };
+    let pre_snapshot = read_state_snapshot(executor.backend_mut(), &touched);
+
     let result = {
         executor
             .set_tracing(true)
-            // .set_debugger(true)
+            // The debugger records per-opcode steps (and their program
+            // counters), which we need to attribute each console.log emission to
+            // its source location.
+            .set_debugger(source_index.is_some())
             .set_trace_printer(false);
-        tx.gas_price = Some(U256::from(1)); //tx.gas_price * 0.000001;
-                                            // tx.gas = tx.gas * 2000;
-        tx.max_priority_fee_per_gas = Some(U256::from(1));
-        tx.max_fee_per_gas = Some(U256::from(1));
+        // `configure_tx_env` classifies the tx by its EIP-2718 type and passes
+        // through the access list and the original fee parameters, so by
+        // default the replay reproduces the real warm/cold gas accounting.
         configure_tx_env(&mut env, &tx);
-        env.tx.gas_limit *= 2000;
+        if relax_gas {
+            // Opt-in: zero the fees and inflate the gas limit so the replay
+            // can't run out of gas or trip basefee/balance checks, at the cost
+            // of faithful gas behavior.
+            env.tx.gas_price = Uint::from(1);
+            env.tx.gas_priority_fee = Some(Uint::from(1));
+            env.tx.gas_limit *= 2000;
+        }
 
         // let mut run_result: RunResult = RunResult {
         //     // original_gas_used: receipt.gas_used.unwrap().as_u64(),
@@ -233,35 +492,432 @@ async fn simulate_tx(
         //     ..Default::default()
         // };
 
-        let logs = if let Some(_) = tx.to {
+        if let Some(_) = tx.to {
             // trace!(tx=?tx.hash,to=?to, "executing call transaction");
             let RawCallResult {
-                reverted: _,
-                gas_used: _,
-                traces: _,
+                reverted,
+                gas_used,
+                traces,
                 logs,
-                // debug: _debug,
+                debug,
                 exit_reason: _,
                 ..
             } = executor.commit_tx_with_env(env).unwrap();
-            logs
+            (logs, gas_used, reverted, traces, debug)
         } else {
             // trace!(tx=?tx.hash, "executing create transaction");
             let DeployResult {
-                gas_used: _,
+                gas_used,
                 logs,
-                traces: _,
-                // debug: run_debug,
+                traces,
+                debug,
                 ..
             }: DeployResult = executor.deploy_with_env(env, None).unwrap();
-            logs
+            (logs, gas_used, false, traces, debug)
+        }
+    };
+
+    let (logs, gas_used, reverted, traces, debug) = result;
+
+    print_logs(&logs);
+
+    // Bind each decoded console message to the source location of its emission.
+    let log_lines = attribute_logs(&logs, debug.as_ref(), source_index);
+
+    let state_diff = if state_diff {
+        let post_snapshot = read_state_snapshot(executor.backend_mut(), &touched);
+        Some(diff_snapshots(&pre_snapshot, &post_snapshot))
+    } else {
+        None
+    };
+
+    let trace = traces
+        .filter(|arena| !arena.arena.is_empty())
+        .map(|arena| build_trace_frame(&arena, 0, abi));
+    let revert_reason = trace.as_ref().and_then(|t| t.revert_reason.clone());
+
+    Ok(Log3Res {
+        log_lines,
+        state_diff,
+        trace,
+        events: decode_events(abi, &logs),
+        gas_used,
+        status: !reverted,
+        revert_reason,
+        logs_bloom: format!("{:#x}", logs_bloom(&logs)),
+    })
+}
+
+/// Recursively turn a `CallTraceArena` node into a decoded `TraceFrame`,
+/// resolving function selectors against the verified ABI.
+fn build_trace_frame(arena: &CallTraceArena, idx: usize, abi: &Contract) -> TraceFrame {
+    let node = &arena.arena[idx];
+    let trace = &node.trace;
+
+    let input = trace.data.to_vec();
+    let output = trace.output.to_vec();
+    let success = trace.success;
+
+    TraceFrame {
+        kind: format!("{:?}", trace.kind),
+        address: format!("{:?}", trace.address),
+        value: format!("{}", trace.value),
+        gas_used: trace.gas_cost,
+        input: format!("0x{}", hex::encode(&input)),
+        output: format!("0x{}", hex::encode(&output)),
+        success,
+        method: decode_method(abi, &input),
+        revert_reason: if success {
+            None
+        } else {
+            decode_revert_reason(&output)
+        },
+        calls: node
+            .children
+            .iter()
+            .map(|child| build_trace_frame(arena, *child, abi))
+            .collect(),
+    }
+}
+
+/// Resolve a call's 4-byte selector to a function name in the ABI.
+fn decode_method(abi: &Contract, input: &[u8]) -> Option<String> {
+    if input.len() < 4 {
+        return None;
+    }
+    let selector = &input[0..4];
+    abi.functions()
+        .find(|f| f.short_signature() == selector)
+        .map(|f| f.name.clone())
+}
+
+/// Decode a standard `Error(string)` revert payload to its message.
+fn decode_revert_reason(output: &[u8]) -> Option<String> {
+    if output.len() < 4 || output[0..4] != [0x08, 0xc3, 0x79, 0xa0] {
+        return None;
+    }
+    let tokens = abi_decode(&[ParamType::String], &output[4..]).ok()?;
+    tokens.into_iter().next()?.into_string()
+}
+
+/// Decode the non-console `Log`s emitted by the transaction against the
+/// contract ABI. Logs that don't match any event in the ABI (notably the
+/// console.log calls) are skipped.
+fn decode_events(abi: &Contract, logs: &[Log]) -> Vec<DecodedEvent> {
+    let mut events = Vec::new();
+    for log in logs {
+        let raw = RawLog {
+            topics: log.topics.clone(),
+            data: log.data.to_vec(),
         };
-        logs
+        for event in abi.events() {
+            if let Ok(parsed) = event.parse_log(raw.clone()) {
+                events.push(DecodedEvent {
+                    address: format!("{:?}", log.address),
+                    event_name: event.name.clone(),
+                    params: parsed
+                        .params
+                        .into_iter()
+                        .map(|p| EventParam {
+                            name: p.name,
+                            value: format!("{:?}", p.value),
+                        })
+                        .collect(),
+                });
+                break;
+            }
+        }
+    }
+    events
+}
+
+/// Flatten the patched metadata back into `(path, content)` pairs, ordered to
+/// line up with the solc source ids referenced by the source map (source 0 for
+/// the single-source case).
+fn metadata_sources(metadata: &Metadata) -> Vec<(String, String)> {
+    match &metadata.source_code {
+        SourceCodeMetadata::SourceCode(code) => {
+            vec![(metadata.contract_name.clone(), code.clone())]
+        }
+        SourceCodeMetadata::Metadata { sources, .. } => {
+            // solc assigns source ids in sorted order of the source names.
+            let mut files: Vec<(String, String)> = sources
+                .iter()
+                .map(|(path, entry)| (path.clone(), entry.content.clone()))
+                .collect();
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            files
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// One decompressed source-map entry (we only need the byte offset and the
+/// source-file id to resolve a location).
+struct SrcEntry {
+    start: i64,
+    file: i64,
+}
+
+/// Maps a runtime program counter to a source `(file, line)` via the deployed
+/// bytecode source map.
+struct SourceMapIndex {
+    pc_to_instr: Vec<Option<usize>>,
+    entries: Vec<SrcEntry>,
+    files: Vec<(String, String)>,
+}
+
+impl SourceMapIndex {
+    fn build(
+        bytecode: &[u8],
+        source_map: Option<&str>,
+        files: Vec<(String, String)>,
+    ) -> Option<Self> {
+        let entries = parse_source_map(source_map?);
+        if entries.is_empty() {
+            return None;
+        }
+        Some(Self {
+            pc_to_instr: pc_to_instruction(bytecode),
+            entries,
+            files,
+        })
+    }
+
+    fn resolve(&self, pc: usize) -> (Option<String>, Option<u32>) {
+        let instr = match self.pc_to_instr.get(pc).copied().flatten() {
+            Some(instr) => instr,
+            None => return (None, None),
+        };
+        let entry = match self.entries.get(instr) {
+            Some(entry) if entry.file >= 0 => entry,
+            _ => return (None, None),
+        };
+        let (path, content) = match self.files.get(entry.file as usize) {
+            Some(file) => file,
+            None => return (None, None),
+        };
+        let offset = entry.start.max(0) as usize;
+        if offset > content.len() {
+            return (Some(path.clone()), None);
+        }
+        let line = content[..offset].bytes().filter(|b| *b == b'\n').count() as u32 + 1;
+        (Some(path.clone()), Some(line))
+    }
+}
+
+/// Parse a solc source map, expanding the `s:l:f:j:m` entries and inheriting
+/// omitted fields from the previous entry.
+fn parse_source_map(source_map: &str) -> Vec<SrcEntry> {
+    let mut entries = Vec::new();
+    let (mut last_start, mut last_file) = (0i64, 0i64);
+    for part in source_map.split(';') {
+        let mut fields = part.split(':');
+        let start = fields
+            .next()
+            .filter(|field| !field.is_empty())
+            .and_then(|field| field.parse().ok())
+            .unwrap_or(last_start);
+        let _length = fields.next();
+        let file = fields
+            .next()
+            .filter(|field| !field.is_empty())
+            .and_then(|field| field.parse().ok())
+            .unwrap_or(last_file);
+        last_start = start;
+        last_file = file;
+        entries.push(SrcEntry { start, file });
+    }
+    entries
+}
+
+/// Build a program-counter → instruction-index table, skipping PUSH immediates.
+fn pc_to_instruction(bytecode: &[u8]) -> Vec<Option<usize>> {
+    let mut map = vec![None; bytecode.len()];
+    let mut pc = 0;
+    let mut instruction = 0;
+    while pc < bytecode.len() {
+        map[pc] = Some(instruction);
+        let op = bytecode[pc];
+        let immediate = if (0x60..=0x7f).contains(&op) {
+            (op - 0x5f) as usize
+        } else {
+            0
+        };
+        pc += 1 + immediate;
+        instruction += 1;
+    }
+    map
+}
+
+/// Decode the console logs and, where possible, attribute each one to the
+/// source location of the `console.log` STATICCALL that emitted it.
+fn attribute_logs(
+    logs: &[Log],
+    debug: Option<&DebugArena>,
+    source_index: Option<&SourceMapIndex>,
+) -> Vec<LogLine> {
+    let messages = decode_console_logs(logs);
+
+    let (debug, index) = match (debug, source_index) {
+        (Some(debug), Some(index)) => (debug, index),
+        _ => {
+            return messages
+                .into_iter()
+                .map(|message| LogLine {
+                    message,
+                    file: None,
+                    line: None,
+                })
+                .collect()
+        }
     };
 
-    print_logs(&result);
+    // ASCII "console.log", right-aligned into a 20-byte address.
+    let console = Address::from_str("0x000000000000000000636f6e736f6c652e6c6f67").unwrap();
+
+    let mut console_pcs = Vec::new();
+    for node in &debug.arena {
+        for step in &node.steps {
+            if step.instruction == 0xFA && console_target(&step.stack) == Some(console) {
+                console_pcs.push(step.pc);
+            }
+        }
+    }
+
+    // Attribution pairs each decoded message with the console STATICCALL that
+    // emitted it by position, which is only sound when the two sequences line
+    // up one-to-one. A console.log in a reverted subcall (whose log is dropped)
+    // or any other skew makes the counts diverge, at which point a positional
+    // zip would pin the *wrong* source line onto a message — worse than none.
+    // So unless the counts match exactly, we emit the messages unattributed.
+    if console_pcs.len() != messages.len() {
+        return messages
+            .into_iter()
+            .map(|message| LogLine {
+                message,
+                file: None,
+                line: None,
+            })
+            .collect();
+    }
+
+    messages
+        .into_iter()
+        .zip(console_pcs)
+        .map(|(message, pc)| {
+            let (file, line) = index.resolve(pc);
+            LogLine {
+                message,
+                file,
+                line,
+            }
+        })
+        .collect()
+}
+
+/// Recover the callee address from the EVM stack at a STATICCALL step.
+fn console_target(stack: &[U256]) -> Option<Address> {
+    if stack.len() < 2 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    stack[stack.len() - 2].to_big_endian(&mut bytes);
+    Some(Address::from_slice(&bytes[12..]))
+}
+
+/// A point-in-time snapshot of a touched account used for state diffing.
+struct AccountSnapshot {
+    balance: U256,
+    nonce: u64,
+    code_hash: B256,
+    storage: Vec<(H256, U256)>,
+}
+
+/// Read the current balance/nonce/code/storage of each touched account from the
+/// executor backend.
+fn read_state_snapshot(
+    db: &mut Backend,
+    touched: &[(Address, Vec<H256>)],
+) -> Vec<(Address, AccountSnapshot)> {
+    touched
+        .iter()
+        .map(|(address, slots)| {
+            let info = db.basic(h160_to_b160(*address)).unwrap().unwrap_or_default();
+            let storage = slots
+                .iter()
+                .map(|slot| {
+                    let value = db
+                        .storage(h160_to_b160(*address), u256_to_ru256(slot.into_uint()))
+                        .unwrap_or_default();
+                    (*slot, ru256_to_u256(value))
+                })
+                .collect();
+            (
+                *address,
+                AccountSnapshot {
+                    balance: ru256_to_u256(info.balance),
+                    nonce: info.nonce,
+                    code_hash: info.code_hash,
+                    storage,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Diff two snapshots of the same touched set, emitting only the accounts and
+/// slots that actually changed.
+fn diff_snapshots(
+    pre: &[(Address, AccountSnapshot)],
+    post: &[(Address, AccountSnapshot)],
+) -> Vec<AccountDiff> {
+    let mut diffs = Vec::new();
+    for ((address, before), (_, after)) in pre.iter().zip(post.iter()) {
+        let storage: Vec<StorageDiff> = before
+            .storage
+            .iter()
+            .zip(after.storage.iter())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((slot, old), (_, new))| StorageDiff {
+                slot: format!("{:?}", slot),
+                old: format!("{:#x}", old),
+                new: format!("{:#x}", new),
+            })
+            .collect();
+
+        let balance_changed = before.balance != after.balance;
+        let nonce_changed = before.nonce != after.nonce;
+        let code_changed = before.code_hash != after.code_hash;
+
+        if storage.is_empty() && !balance_changed && !nonce_changed && !code_changed {
+            continue;
+        }
+
+        diffs.push(AccountDiff {
+            address: format!("{:?}", address),
+            balance_old: balance_changed.then(|| format!("{:#x}", before.balance)),
+            balance_new: balance_changed.then(|| format!("{:#x}", after.balance)),
+            nonce_old: nonce_changed.then_some(before.nonce),
+            nonce_new: nonce_changed.then_some(after.nonce),
+            code_changed,
+            storage,
+        });
+    }
+    diffs
+}
 
-    Ok(decode_console_logs(&result))
+/// Accrue a logs bloom over the emitted events, following the receipt shape of
+/// typed-receipt libraries.
+fn logs_bloom(logs: &[Log]) -> Bloom {
+    let mut bloom = Bloom::default();
+    for log in logs {
+        bloom.accrue(BloomInput::Raw(log.address.as_bytes()));
+        for topic in &log.topics {
+            bloom.accrue(BloomInput::Raw(topic.as_bytes()));
+        }
+    }
+    bloom
 }
 
 fn prepare_fork_state_plain(
@@ -294,11 +950,12 @@ fn prepare_fork_state_plain(
     Ok(())
 }
 
-async fn prepare_fork_state_debug(
-    executor: &mut Executor,
+/// Fetch the `prestateTracer` output for a transaction. The default (non-diff)
+/// form returns the pre-state of exactly the accounts and slots the tx touched.
+async fn fetch_prestate(
     provider: &foundry_common::RetryProvider,
     tx_hash: H256,
-) -> Result<()> {
+) -> Result<PreStateMode> {
     let states = provider
         .debug_trace_transaction(
             tx_hash,
@@ -309,25 +966,248 @@ async fn prepare_fork_state_debug(
                 ..Default::default()
             },
         )
-        .await
-        .unwrap();
+        .await?;
 
-    let test = match states {
-        GethTrace::Known(GethTraceFrame::PreStateTracer(x)) => x,
-        _ => panic!("Unknown trace type"),
-    };
+    match states {
+        GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Default(mode))) => Ok(mode),
+        _ => Err(eyre::eyre!("unexpected trace type from prestateTracer")),
+    }
+}
+
+async fn prepare_fork_state_debug(
+    executor: &mut Executor,
+    provider: &foundry_common::RetryProvider,
+    tx_hash: H256,
+) -> Result<()> {
+    let mode = fetch_prestate(provider, tx_hash).await?;
+    apply_pre_state(executor.backend_mut(), PreStateFrame::Default(mode)).unwrap();
+    Ok(())
+}
+
+/// Seed the in-memory backend with state that has been Merkle-proof verified
+/// against the fork block's `stateRoot`, so an untrusted RPC cannot silently
+/// feed wrong storage or balances.
+///
+/// We pre-warm the touched accounts/slots from the prestate tracer, then batch
+/// one `eth_getProof` per account to avoid per-opcode round trips. Each account
+/// proof is verified against the block `stateRoot` and each storage proof
+/// against the account's `storageHash`; code is checked against `codeHash`.
+async fn prepare_fork_state_verified(
+    executor: &mut Executor,
+    provider: &foundry_common::RetryProvider,
+    tx_hash: H256,
+    fork_block: u64,
+) -> Result<()> {
+    let mode = fetch_prestate(provider, tx_hash).await?;
+    let block_id = BlockId::from(fork_block);
+
+    let header = provider
+        .get_block(fork_block)
+        .await?
+        .ok_or_else(|| eyre::eyre!("fork block {} not found", fork_block))?;
+    let state_root = header.state_root;
+
+    let empty_code_hash = H256::from_slice(&keccak256([])[..]);
+
+    for (address, account) in mode.0 {
+        let slots: Vec<H256> = account
+            .storage
+            .as_ref()
+            .map(|storage| storage.keys().cloned().collect())
+            .unwrap_or_default();
+
+        let proof = provider
+            .get_proof(address, slots, Some(block_id))
+            .await?;
+
+        // Verify the account's inclusion (or exclusion) against the state root.
+        let leaf = verify_mpt_proof(state_root, address.as_bytes(), &proof.account_proof)?;
+        if let Some(bytes) = &leaf {
+            verify_account_leaf(bytes, &proof)?;
+        }
+
+        let mut account_info = AccountInfo::default();
+        account_info.nonce = proof.nonce.as_u64();
+        account_info.balance = proof.balance.into();
+
+        // Accounts with no code are EOAs; only fetch and verify code otherwise.
+        if proof.code_hash != empty_code_hash && proof.code_hash != H256::zero() {
+            let code = provider.get_code(address, Some(block_id)).await?;
+            if H256::from_slice(&keccak256(&code)[..]) != proof.code_hash {
+                return Err(eyre::eyre!("code hash mismatch for {:?}", address));
+            }
+            account_info.code_hash = B256::from_slice(proof.code_hash.as_bytes());
+            account_info.code = Some(Bytecode::new_raw(code.to_vec().into()));
+        }
+
+        executor.backend_mut().insert_account_info(address, account_info);
+
+        for storage_proof in &proof.storage_proof {
+            // A storage leaf holds `RLP(value)`, not the raw value, so the
+            // proven bytes must be RLP-decoded before comparing against the
+            // node-reported slot value (an absent slot proves to zero).
+            let proven =
+                verify_mpt_proof(proof.storage_hash, storage_proof.key.as_bytes(), &storage_proof.proof)?;
+            let value = match &proven {
+                Some(bytes) => U256::from_big_endian(&Rlp::new(bytes).data()?),
+                None => U256::zero(),
+            };
+            if value != storage_proof.value {
+                return Err(eyre::eyre!(
+                    "storage slot {:?} value does not match its proof",
+                    storage_proof.key
+                ));
+            }
+            executor.backend_mut().insert_account_storage(
+                address,
+                storage_proof.key.into_uint().into(),
+                storage_proof.value.into(),
+            )?;
+        }
+    }
 
-    apply_pre_state(executor.backend_mut(), test).unwrap();
     Ok(())
 }
 
-fn get_provider(config: &Config) -> foundry_common::RetryProvider {
-    let url = config.get_rpc_url_or_localhost_http().unwrap();
+/// Verify the RLP-decoded account leaf `[nonce, balance, storageHash, codeHash]`
+/// matches the fields the node returned alongside the proof.
+fn verify_account_leaf(leaf: &[u8], proof: &EIP1186ProofResponse) -> Result<()> {
+    let rlp = Rlp::new(leaf);
+    let nonce: U256 = rlp.val_at(0)?;
+    let balance: U256 = rlp.val_at(1)?;
+    let storage_hash: H256 = rlp.val_at(2)?;
+    let code_hash: H256 = rlp.val_at(3)?;
+
+    if nonce != proof.nonce
+        || balance != proof.balance
+        || storage_hash != proof.storage_hash
+        || code_hash != proof.code_hash
+    {
+        return Err(eyre::eyre!("account leaf does not match the proof fields"));
+    }
+    Ok(())
+}
+
+/// Walk a Merkle-Patricia proof for `key` under `root`, returning the value at
+/// the terminal leaf, or `None` when the key is proven absent.
+fn verify_mpt_proof(root: H256, key: &[u8], proof: &[Bytes]) -> Result<Option<Vec<u8>>> {
+    let nibbles = to_nibbles(&keccak256(key)[..]);
+    let mut expected = root.as_bytes().to_vec();
+    let mut pos = 0usize;
+
+    for (depth, node) in proof.iter().enumerate() {
+        // Every referenced node must hash to the value its parent pointed at
+        // (nodes under 32 bytes are inlined and compared verbatim).
+        if node.as_ref().len() >= 32 {
+            if keccak256(node.as_ref())[..] != expected[..] {
+                return Err(eyre::eyre!("proof node hash mismatch at depth {}", depth));
+            }
+        } else if node.as_ref() != expected.as_slice() {
+            return Err(eyre::eyre!("inlined proof node mismatch at depth {}", depth));
+        }
+
+        let rlp = Rlp::new(node.as_ref());
+        match rlp.item_count()? {
+            17 => {
+                if pos == nibbles.len() {
+                    let value = rlp.at(16)?.data()?.to_vec();
+                    return Ok(if value.is_empty() { None } else { Some(value) });
+                }
+                let branch = rlp.at(nibbles[pos] as usize)?;
+                if branch.is_empty() {
+                    return Ok(None);
+                }
+                expected = node_ref(&branch)?;
+                pos += 1;
+            }
+            2 => {
+                let path = rlp.at(0)?.data()?.to_vec();
+                let (is_leaf, path_nibbles) = decode_hp(&path);
+                if nibbles.len() - pos < path_nibbles.len()
+                    || nibbles[pos..pos + path_nibbles.len()] != path_nibbles[..]
+                {
+                    // The shared path diverges, which proves the key is absent.
+                    return Ok(None);
+                }
+                pos += path_nibbles.len();
+                if is_leaf {
+                    return Ok(Some(rlp.at(1)?.data()?.to_vec()));
+                }
+                expected = node_ref(&rlp.at(1)?)?;
+            }
+            count => return Err(eyre::eyre!("unexpected MPT node with {} items", count)),
+        }
+    }
+
+    Err(eyre::eyre!("proof exhausted before reaching a terminal node"))
+}
+
+/// A child reference in a branch/extension node is either a 32-byte hash or a
+/// small node inlined directly.
+fn node_ref(rlp: &Rlp) -> Result<Vec<u8>> {
+    if rlp.is_data() {
+        Ok(rlp.data()?.to_vec())
+    } else {
+        Ok(rlp.as_raw().to_vec())
+    }
+}
+
+/// Split a byte slice into its 4-bit nibbles, high nibble first.
+fn to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    let mut nibbles = Vec::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0f);
+    }
+    nibbles
+}
+
+/// Decode a hex-prefix encoded path, returning `(is_leaf, nibbles)`.
+fn decode_hp(path: &[u8]) -> (bool, Vec<u8>) {
+    let mut nibbles = to_nibbles(path);
+    let flag = nibbles.first().copied().unwrap_or(0);
+    let is_leaf = flag & 0x02 != 0;
+    let odd = flag & 0x01 != 0;
+    let start = if odd { 1 } else { 2 };
+    (is_leaf, nibbles.split_off(start.min(nibbles.len())))
+}
+
+async fn get_provider(config: &Config) -> Result<foundry_common::RetryProvider> {
+    let url = config.get_rpc_url_or_localhost_http()?;
     let chain = config.chain_id.unwrap_or_default();
-    foundry_common::ProviderBuilder::new(url.as_ref())
-        .chain(chain)
-        .build()
-        .unwrap()
+
+    // `endpoint` may be a comma-separated list of URLs. We try each in turn and
+    // fail over to the next when a node can't be built *or* can't be reached:
+    // `build()` alone never connects, so we probe each candidate with a cheap
+    // `eth_blockNumber` and only keep the first that actually answers, skipping
+    // past a dead or rate-limited node. Each URL's scheme (`http(s)://` vs
+    // `ws(s)://`) selects the transport, and the chosen `RetryProvider` then
+    // retries transient request failures against that endpoint.
+    let mut last_err = None;
+    for endpoint in url.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let provider = match foundry_common::ProviderBuilder::new(endpoint)
+            .chain(chain)
+            .build()
+        {
+            Ok(provider) => provider,
+            Err(err) => {
+                last_err = Some(err);
+                continue;
+            }
+        };
+        match provider.get_block_number().await {
+            Ok(_) => return Ok(provider),
+            Err(err) => last_err = Some(eyre::eyre!(err)),
+        }
+    }
+
+    Err(eyre::eyre!(
+        "could not reach any RPC endpoint in `{}`: {}",
+        url,
+        last_err
+            .map(|err| err.to_string())
+            .unwrap_or_else(|| "no endpoints provided".to_string())
+    ))
 }
 
 fn evm_spec(evm: &EvmVersion) -> SpecId {
@@ -336,14 +1216,40 @@ fn evm_spec(evm: &EvmVersion) -> SpecId {
         EvmVersion::Berlin => SpecId::BERLIN,
         EvmVersion::London => SpecId::LONDON,
         EvmVersion::Paris => SpecId::MERGE,
+        EvmVersion::Shanghai => SpecId::SHANGHAI,
+        EvmVersion::Cancun => SpecId::CANCUN,
         _ => panic!("Unsupported EVM version"),
     }
 }
 
+/// Fallback hardfork schedule keyed by `(chainid, block_number)`.
+///
+/// The `evm_version` in the Etherscan metadata is only the version the
+/// contract was compiled for; a fork-aware client selects the active spec per
+/// block rather than assuming a single version. A mismatch here silently
+/// changes opcode availability (PUSH0, TLOAD/TSTORE) and gas accounting, so we
+/// map the block being replayed back to the fork that was live at that height.
+fn hardfork_spec(chainid: u64, block_number: u64) -> SpecId {
+    // (Shanghai, Cancun) activation blocks per chain.
+    let (shanghai, cancun) = match chainid {
+        1 => (17_034_870u64, 19_426_587u64),
+        _ => return SpecId::MERGE,
+    };
+
+    if block_number >= cancun {
+        SpecId::CANCUN
+    } else if block_number >= shanghai {
+        SpecId::SHANGHAI
+    } else {
+        SpecId::MERGE
+    }
+}
+
 fn configure_env_for_executor(
     executor: &Executor,
     tx_block_number: u64,
     block: &Block<Transaction>,
+    relax_gas: bool,
 ) -> Env {
     let mut env = executor.env().clone();
     env.block.number = Uint::from(tx_block_number);
@@ -355,9 +1261,14 @@ fn configure_env_for_executor(
         None => None,
         Some(x) => Option::Some(x.into()),
     };
-    env.block.basefee = Uint::from(1); //WARN SIMULATING //block.base_fee_per_gas.unwrap_or_default().into();
-    let gas_limit = block.gas_limit.as_u64() * 2000;
-    env.block.gas_limit = Uint::from(gas_limit);
+
+    if relax_gas {
+        env.block.basefee = Uint::from(1);
+        env.block.gas_limit = Uint::from(block.gas_limit.as_u64() * 2000);
+    } else {
+        env.block.basefee = block.base_fee_per_gas.unwrap_or_default().into();
+        env.block.gas_limit = Uint::from(block.gas_limit.as_u64());
+    }
 
     return env;
 }
@@ -373,6 +1284,55 @@ struct AccountOverride {
 
 type StateOverride = HashMap<Address, AccountOverride>;
 
+/// Convert the serializable overrides from the request payload into the
+/// internal `StateOverride` map.
+fn convert_state_overrides(
+    user: Option<HashMap<String, AccountOverrideJson>>,
+) -> Result<StateOverride> {
+    let mut out = StateOverride::new();
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(out),
+    };
+
+    for (address, override_json) in user {
+        out.insert(
+            Address::from_str(&address)?,
+            AccountOverride {
+                nonce: override_json.nonce,
+                code: override_json.code.map(|c| Bytes::from_str(&c)).transpose()?,
+                balance: override_json.balance.as_deref().map(parse_u256).transpose()?,
+                state: convert_storage_map(override_json.state)?,
+                state_diff: convert_storage_map(override_json.state_diff)?,
+            },
+        );
+    }
+
+    Ok(out)
+}
+
+fn convert_storage_map(
+    storage: Option<HashMap<String, String>>,
+) -> Result<Option<HashMap<H256, H256>>> {
+    storage
+        .map(|entries| {
+            entries
+                .into_iter()
+                .map(|(key, value)| Ok((H256::from_str(&key)?, H256::from_str(&value)?)))
+                .collect::<Result<HashMap<_, _>>>()
+        })
+        .transpose()
+}
+
+/// Parse a balance/numeric override from either `0x`-hex or decimal.
+fn parse_u256(value: &str) -> Result<U256> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16)?,
+        None => U256::from_dec_str(value)?,
+    };
+    Ok(parsed)
+}
+
 fn apply_state_override(db: &mut Backend, overrides: StateOverride) -> DatabaseResult<()> {
     for (account, account_overrides) in overrides.iter() {
         let mut account_info = db.basic(h160_to_b160(*account))?.unwrap_or_default();
@@ -401,18 +1361,36 @@ fn apply_state_override(db: &mut Backend, overrides: StateOverride) -> DatabaseR
             }
             (None, None) => (),
             (Some(new_account_state), None) => {
-                db.active_fork_db_mut().unwrap().replace_account_storage(
-                    h160_to_b160(*account),
-                    new_account_state
-                        .iter()
-                        .map(|(key, value)| {
-                            (
-                                u256_to_ru256(key.into_uint()),
-                                u256_to_ru256(value.into_uint()),
-                            )
-                        })
-                        .collect(),
-                )?;
+                // `replace_account_storage` wipes the account's existing storage
+                // before writing the supplied slots, but it only exists on the
+                // fork db. The `Prestate`/`Verified` methods run on an in-memory
+                // backend (`Backend::spawn(None)`) with no fork, so there we
+                // write the slots through the in-memory API instead of
+                // unwrapping a `None` fork db. That path does not clear the
+                // account's already-seeded slots.
+                match db.active_fork_db_mut() {
+                    Some(fork_db) => fork_db.replace_account_storage(
+                        h160_to_b160(*account),
+                        new_account_state
+                            .iter()
+                            .map(|(key, value)| {
+                                (
+                                    u256_to_ru256(key.into_uint()),
+                                    u256_to_ru256(value.into_uint()),
+                                )
+                            })
+                            .collect(),
+                    )?,
+                    None => {
+                        for (key, value) in new_account_state.iter() {
+                            db.insert_account_storage(
+                                *account,
+                                key.into_uint().into(),
+                                value.into_uint().into(),
+                            )?;
+                        }
+                    }
+                }
             }
             (None, Some(account_state_diff)) => {
                 for (key, value) in account_state_diff.iter() {
@@ -453,8 +1431,8 @@ pub fn apply_pre_state(db: &mut Backend, pre_state: PreStateFrame) -> DatabaseRe
 
         if let Some(storage) = account_overrides.storage {
             for (key, value) in storage.iter() {
-                db.active_fork_db_mut().unwrap().insert_account_storage(
-                    (account).into(),
+                db.insert_account_storage(
+                    account,
                     key.into_uint().into(),
                     value.into_uint().into(),
                 )?;
@@ -469,3 +1447,139 @@ fn print_logs(logs: &[Log]) {
         eprint!("{:?}\n", log);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers_core::utils::rlp::RlpStream;
+
+    #[test]
+    fn to_nibbles_splits_high_then_low() {
+        assert_eq!(to_nibbles(&[0xab, 0x0c]), vec![0x0a, 0x0b, 0x00, 0x0c]);
+    }
+
+    #[test]
+    fn decode_hp_handles_all_four_prefixes() {
+        // even extension, even leaf, odd extension, odd leaf.
+        assert_eq!(decode_hp(&[0x00, 0xab]), (false, vec![0x0a, 0x0b]));
+        assert_eq!(decode_hp(&[0x20, 0xab]), (true, vec![0x0a, 0x0b]));
+        assert_eq!(decode_hp(&[0x1a, 0xbc]), (false, vec![0x0a, 0x0b, 0x0c]));
+        assert_eq!(decode_hp(&[0x3a, 0xbc]), (true, vec![0x0a, 0x0b, 0x0c]));
+    }
+
+    #[test]
+    fn parse_source_map_inherits_omitted_fields() {
+        let entries = parse_source_map("1:2:0:-;:9:1;:::");
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].start, entries[0].file), (1, 0));
+        // start inherits 1, file becomes 1.
+        assert_eq!((entries[1].start, entries[1].file), (1, 1));
+        // everything inherited from the previous entry.
+        assert_eq!((entries[2].start, entries[2].file), (1, 1));
+    }
+
+    #[test]
+    fn pc_to_instruction_skips_push_immediates() {
+        // PUSH1 0x01 (2 bytes), STOP.
+        assert_eq!(
+            pc_to_instruction(&[0x60, 0x01, 0x00]),
+            vec![Some(0), None, Some(1)]
+        );
+    }
+
+    #[test]
+    fn hardfork_spec_selects_by_block_height() {
+        assert_eq!(hardfork_spec(1, 17_000_000), SpecId::MERGE);
+        assert_eq!(hardfork_spec(1, 18_000_000), SpecId::SHANGHAI);
+        assert_eq!(hardfork_spec(1, 20_000_000), SpecId::CANCUN);
+        // Chains without a schedule fall back to the merge spec.
+        assert_eq!(hardfork_spec(137, 50_000_000), SpecId::MERGE);
+    }
+
+    #[test]
+    fn parse_u256_accepts_hex_and_decimal() {
+        assert_eq!(parse_u256("0x10").unwrap(), U256::from(16));
+        assert_eq!(parse_u256("255").unwrap(), U256::from(255));
+    }
+
+    #[test]
+    fn verify_account_leaf_matches_proof_fields() {
+        let nonce = U256::from(7);
+        let balance = U256::from(1000);
+        let storage_hash = H256::repeat_byte(0xaa);
+        let code_hash = H256::repeat_byte(0xbb);
+
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&nonce);
+        stream.append(&balance);
+        stream.append(&storage_hash);
+        stream.append(&code_hash);
+        let leaf = stream.out().to_vec();
+
+        let proof = EIP1186ProofResponse {
+            address: Address::zero(),
+            balance,
+            code_hash,
+            nonce,
+            storage_hash,
+            account_proof: vec![],
+            storage_proof: vec![],
+        };
+        verify_account_leaf(&leaf, &proof).unwrap();
+
+        // A single flipped field must be rejected.
+        let mut wrong = proof.clone();
+        wrong.nonce = U256::from(8);
+        assert!(verify_account_leaf(&leaf, &wrong).is_err());
+    }
+
+    #[test]
+    fn verify_mpt_proof_reads_a_single_leaf_root() {
+        // A one-node trie whose root leaf holds the entire 64-nibble path.
+        let key = [0x12u8, 0x34];
+        let mut path = vec![0x20u8]; // even-length leaf prefix
+        path.extend_from_slice(&keccak256(key));
+        let value = vec![0x05u8];
+
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&path.as_slice());
+        stream.append(&value.as_slice());
+        let node = stream.out().to_vec();
+        let root = H256::from_slice(&keccak256(&node));
+        let proof = vec![Bytes::from(node)];
+
+        let got = verify_mpt_proof(root, &key, &proof).unwrap();
+        assert_eq!(got, Some(value));
+
+        // The node must hash to the claimed root.
+        assert!(verify_mpt_proof(H256::repeat_byte(0x01), &key, &proof).is_err());
+    }
+
+    #[test]
+    fn verify_mpt_proof_returns_rlp_encoded_storage_value() {
+        // A storage leaf holds `RLP(value)`; for a multi-byte value like
+        // `0x0100` that carries a length prefix, so decoding the proven bytes
+        // (not comparing them verbatim) is what recovers the slot value.
+        let key = [0xbeu8, 0xef];
+        let slot_value = U256::from(0x0100);
+        let mut value_rlp = RlpStream::new();
+        value_rlp.append(&slot_value);
+        let encoded = value_rlp.out().to_vec();
+        assert!(encoded.len() > 1, "value should carry an RLP length prefix");
+
+        let mut path = vec![0x20u8];
+        path.extend_from_slice(&keccak256(key));
+
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&path.as_slice());
+        stream.append(&encoded.as_slice());
+        let node = stream.out().to_vec();
+        let root = H256::from_slice(&keccak256(&node));
+        let proof = vec![Bytes::from(node)];
+
+        let proven = verify_mpt_proof(root, &key, &proof).unwrap().unwrap();
+        // Verbatim comparison would mismatch; RLP-decoding recovers the value.
+        assert_ne!(U256::from_big_endian(&proven), slot_value);
+        assert_eq!(U256::from_big_endian(&Rlp::new(&proven).data().unwrap()), slot_value);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
 
@@ -7,12 +9,14 @@ pub enum MethodType {
     Plain = 0,
     #[default]
     Prestate = 1,
+    Verified = 2,
 }
 
 impl From<u8> for MethodType {
     fn from(v: u8) -> Self {
         match v {
             0 => MethodType::Plain,
+            2 => MethodType::Verified,
             _ => MethodType::Prestate,
         }
     }
@@ -26,9 +30,134 @@ pub struct Log3Json {
     pub tx_hash: String,
     pub endpoint: String,
     pub method: Option<MethodType>,
+    /// When set, zero the tx fees and inflate the gas limit rather than
+    /// replaying with the transaction's real gas economics.
+    pub relax_gas: Option<bool>,
+    /// Caller-supplied state overrides, keyed by address, merged into the
+    /// override map before execution. For the target contract the console
+    /// bytecode takes precedence over a `code` override.
+    pub overrides: Option<HashMap<String, AccountOverrideJson>>,
+    /// When set, report the storage/balance/nonce changes the tx made.
+    pub state_diff: Option<bool>,
+    /// Batch mode: replay each of these tx hashes (in block order).
+    pub tx_hashes: Option<Vec<String>>,
+    /// Batch mode: replay every transaction in this block.
+    pub block_number: Option<u64>,
 }
 
+/// Console logs for a single transaction in a batch replay.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub struct Log3Res {
+pub struct TxLog3 {
+    pub tx_hash: String,
     pub log_lines: Vec<String>,
 }
+
+/// Result of replaying multiple transactions, grouped per transaction.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BatchLog3Res {
+    pub results: Vec<TxLog3>,
+}
+
+/// Serializable form of a single account's state override. `balance` is
+/// accepted either as a `0x`-prefixed hex or a decimal string; storage keys and
+/// values are 32-byte hex words. `state_diff` patches individual slots; `state`
+/// replaces the account's storage wholesale on a forked backend (`Plain`),
+/// while on the in-memory backend (`Prestate`/`Verified`) it writes the given
+/// slots and keeps the account's other seeded slots.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct AccountOverrideJson {
+    pub nonce: Option<u64>,
+    pub balance: Option<String>,
+    pub code: Option<String>,
+    pub state: Option<HashMap<String, String>>,
+    pub state_diff: Option<HashMap<String, String>>,
+}
+
+/// A single console.log line, attributed to the source location that emitted it
+/// when the bytecode source map could resolve it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogLine {
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+}
+
+/// A single decoded parameter of an emitted event.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EventParam {
+    pub name: String,
+    pub value: String,
+}
+
+/// A non-console `Log` decoded against the contract ABI.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DecodedEvent {
+    pub address: String,
+    pub event_name: String,
+    pub params: Vec<EventParam>,
+}
+
+/// One frame of the execution call tree.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TraceFrame {
+    /// CALL / DELEGATECALL / STATICCALL / CREATE, etc.
+    pub kind: String,
+    pub address: String,
+    pub value: String,
+    pub gas_used: u64,
+    pub input: String,
+    pub output: String,
+    pub success: bool,
+    /// Resolved method name when the selector matched the ABI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+    pub calls: Vec<TraceFrame>,
+}
+
+/// A single storage slot that the transaction changed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StorageDiff {
+    pub slot: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The net change to one account caused by the transaction.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AccountDiff {
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_old: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_new: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce_old: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce_new: Option<u64>,
+    pub code_changed: bool,
+    pub storage: Vec<StorageDiff>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Log3Res {
+    pub log_lines: Vec<LogLine>,
+    /// Per-account changes the transaction made, when a state diff was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<Vec<AccountDiff>>,
+    /// The execution call tree, when tracing produced one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceFrame>,
+    /// Real events emitted by the transaction, decoded against the ABI.
+    pub events: Vec<DecodedEvent>,
+    pub gas_used: u64,
+    /// `true` when the transaction succeeded, `false` when it reverted.
+    pub status: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
+    /// Hex-encoded logs bloom accrued from the emitted events.
+    pub logs_bloom: String,
+}